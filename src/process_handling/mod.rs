@@ -10,7 +10,8 @@ use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command};
-use tracing::{error, info, trace_span};
+use std::sync::OnceLock;
+use tracing::{error, info, trace_span, warn};
 
 /// Handle to a test currently either PID or a `std::process::Child`
 pub enum TestHandle {
@@ -29,6 +30,29 @@ pub struct RunningProcessHandle {
 
 impl RunningProcessHandle {
     pub fn new(path: PathBuf, cmd: &mut Command, config: &Config) -> Result<Self, RunError> {
+        Self::new_with_pool_reset(path, cmd, config, &[])
+    }
+
+    /// Like `new`, but first deletes any of `pool_profraws` that already exist on disk.
+    ///
+    /// `%Nm` pool files are merged into in place across invocations rather than freshly
+    /// created, unlike the one-file-per-PID `%p` scheme. A plain existing-vs-new snapshot diff
+    /// would see a pool file left over from an earlier run, already present before this spawn,
+    /// and treat whatever gets merged into it as "pre-existing" rather than new coverage,
+    /// silently dropping it on every run except a pristine `target` dir. Callers driving a pool
+    /// run must pass the pool's expected file paths so they're reset before the snapshot.
+    pub fn new_with_pool_reset(
+        path: PathBuf,
+        cmd: &mut Command,
+        config: &Config,
+        pool_profraws: &[PathBuf],
+    ) -> Result<Self, RunError> {
+        for pool_file in pool_profraws {
+            if pool_file.is_file() {
+                fs::remove_file(pool_file)?;
+            }
+        }
+
         let child = cmd.spawn()?;
         let existing_profraws = fs::read_dir(config.root())?
             .into_iter()
@@ -76,7 +100,17 @@ pub fn get_test_coverage(
     let handle = launch_test(test, config, ignored, logger)?;
     if let Some(handle) = handle {
         match collect_coverage(test.path(), handle, analysis, config, logger) {
-            Ok(t) => Ok(Some(t)),
+            Ok((traces, ret_code)) => {
+                if ret_code != 0 {
+                    warn!(
+                        "test binary {} exited with failure code {}, but coverage data was \
+                         collected and `--ignore-run-fail` is set so reporting will continue",
+                        test.path().display(),
+                        ret_code
+                    );
+                }
+                Ok(Some((traces, ret_code)))
+            }
             Err(e) => Err(RunError::TestCoverage(e.to_string())),
         }
     } else {
@@ -108,6 +142,10 @@ fn launch_test(
             let res = execute_test(test, ignored, config)?;
             Ok(Some(res))
         }
+        TraceEngine::Nextest => {
+            let res = execute_test_nextest(test, ignored, config)?;
+            Ok(Some(res))
+        }
         e => {
             error!(
                 "Tarpaulin cannot execute tests with {:?} on this platform",
@@ -132,6 +170,94 @@ cfg_if::cfg_if! {
     }
 }
 
+/// Runs `cargo test --doc` under LLVM instrumentation so doctests contribute to coverage the
+/// same way ordinary test binaries do.
+///
+/// Rustdoc compiles and runs each doctest as its own short-lived, throwaway process, so there's
+/// no single long-lived `TestBinary` to hand to `generate_tracemap` the way `execute_test` does.
+/// We first build the doctests without running them, asking rustdoc to persist the resulting
+/// `--test` binaries to disk with `--persist-doctests`, then instrument and run each of those
+/// binaries individually so its profraws and its tracemap both line up against the same real
+/// object file, merging every doctest's `TraceMap` into one before returning.
+pub fn get_doctest_coverage(
+    config: &Config,
+    analysis: &HashMap<PathBuf, LineAnalysis>,
+    logger: &Option<EventLog>,
+) -> Result<Option<(TraceMap, i32)>, RunError> {
+    if !matches!(config.engine(), TraceEngine::Llvm) {
+        error!("Doctest coverage is only supported with the Llvm engine");
+        return Ok(None);
+    }
+    info!("building doctests for coverage");
+    env::set_current_dir(config.root())?;
+
+    let persist_dir = config.root().join("target").join("tarpaulin").join("doctests");
+    fs::create_dir_all(&persist_dir)?;
+
+    let status = Command::new("cargo")
+        .arg("test")
+        .arg("--doc")
+        .arg("--no-run")
+        .arg("-Z")
+        .arg("unstable-options")
+        .arg("--persist-doctests")
+        .arg(&persist_dir)
+        .args(&config.varargs)
+        .status()?;
+    if !status.success() {
+        return Err(RunError::TestCoverage(
+            "failed to build doctests for coverage".to_string(),
+        ));
+    }
+
+    let doctest_bins: Vec<PathBuf> = fs::read_dir(&persist_dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .collect();
+
+    if doctest_bins.is_empty() {
+        info!("no persisted doctest binaries found, skipping doctest coverage");
+        return Ok(None);
+    }
+
+    let mut traces = TraceMap::new();
+    let mut ret_code = 0;
+    for bin in &doctest_bins {
+        info!("running doctest binary {}", bin.display());
+        let mut envars: Vec<(String, String)> = env::vars().collect();
+        if config.verbose {
+            envars.push(("RUST_BACKTRACE".to_string(), "1".to_string()));
+        }
+        let bin_name = bin.file_name().and_then(|n| n.to_str()).unwrap_or("doctest");
+        envars.push((
+            "LLVM_PROFILE_FILE".to_string(),
+            profraw_file_pattern(bin_name, config),
+        ));
+
+        let mut cmd = Command::new(bin);
+        cmd.envs(envars);
+
+        let pool_profraws = match active_profraw_pool_size(config) {
+            Some(pool_size) => pool_profraw_paths(config.root(), bin_name, pool_size),
+            None => Vec::new(),
+        };
+        let handle =
+            RunningProcessHandle::new_with_pool_reset(bin.clone(), &mut cmd, config, &pool_profraws)?;
+        match collect_coverage(bin, handle.into(), analysis, config, logger) {
+            Ok((bin_traces, code)) => {
+                traces.merge(&bin_traces);
+                if code != 0 {
+                    ret_code = code;
+                }
+            }
+            Err(e) => return Err(RunError::TestCoverage(e.to_string())),
+        }
+    }
+
+    Ok(Some((traces, ret_code)))
+}
+
 /// Collects the coverage data from the launched test
 pub(crate) fn collect_coverage(
     test_path: &Path,
@@ -157,35 +283,99 @@ pub(crate) fn collect_coverage(
             }
         }
     }
+    if ret_code != 0 && !config.ignore_run_fail {
+        return Err(RunError::TestCoverage(format!(
+            "test run for {} failed with exit code {} (pass --ignore-run-fail to report \
+             coverage anyway)",
+            test_path.display(),
+            ret_code
+        )));
+    }
     Ok((traces, ret_code))
 }
 
-/// Launches the test executable
-fn execute_test(test: &TestBinary, ignored: bool, config: &Config) -> Result<TestHandle, RunError> {
-    info!("running {}", test.path().display());
-    let _ = match test.manifest_dir() {
-        Some(md) => env::set_current_dir(&md),
-        None => env::set_current_dir(&config.root()),
-    };
+/// Minimum LLVM version that supports the `%Nm` online-merge pool specifier in
+/// `LLVM_PROFILE_FILE` (it was added to compiler-rt's profile runtime in LLVM 13).
+const MIN_LLVM_VERSION_FOR_PROFRAW_POOL: u32 = 13;
 
-    let mut envars: Vec<(String, String)> = Vec::new();
+/// Whether the active `rustc`'s bundled LLVM is new enough to support `%Nm` pool merging.
+///
+/// `profraw_file_pattern` is called once per `TestBinary` in the run, so the underlying
+/// `rustc --version --verbose` shell-out is memoized rather than repeated for every binary.
+fn supports_profraw_pool() -> bool {
+    static SUPPORTS_POOL: OnceLock<bool> = OnceLock::new();
+    *SUPPORTS_POOL.get_or_init(|| {
+        let Ok(output) = Command::new("rustc").arg("--version").arg("--verbose").output() else {
+            return false;
+        };
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|line| line.strip_prefix("LLVM version: "))
+            .and_then(|version| version.split('.').next())
+            .and_then(|major| major.parse::<u32>().ok())
+            .map(|major| major >= MIN_LLVM_VERSION_FOR_PROFRAW_POOL)
+            .unwrap_or(false)
+    })
+}
 
-    for (key, value) in env::vars() {
-        envars.push((key.to_string(), value.to_string()));
+/// Returns the pool size to use if pool merging is both requested and
+/// supported by the active toolchain, logging a fallback warning the one time per process it
+/// turns out to be requested but unsupported.
+fn active_profraw_pool_size(config: &Config) -> Option<u32> {
+    static FALLBACK_WARNED: std::sync::Once = std::sync::Once::new();
+    let pool_size = config.profraw_pool_size.filter(|&n| n > 0)?;
+    if supports_profraw_pool() {
+        Some(pool_size)
+    } else {
+        FALLBACK_WARNED.call_once(|| {
+            warn!(
+                "profraw pool merging was requested but the active rustc's LLVM runtime is older \
+                 than {}, which doesn't support the %m specifier; falling back to one profraw per \
+                 process",
+                MIN_LLVM_VERSION_FOR_PROFRAW_POOL
+            );
+        });
+        None
     }
-    let mut argv = vec![];
-    if ignored {
-        argv.push("--ignored".to_string());
+}
+
+/// Picks the `LLVM_PROFILE_FILE` template for a given test binary.
+///
+/// By default every instrumented process writes its own `{file}_%p.profraw`, which for large
+/// suites with many short-lived processes can produce an enormous number of files and make the
+/// merge step slow. When `config.profraw_pool_size` is set, we switch to LLVM's online-merging
+/// pool form `{file}_%Nm.profraw`: concurrently running processes atomically merge their
+/// counters into a bounded set of at most N pool files instead of one per PID. Pool merging
+/// isn't supported by every LLVM profile runtime, so we gate it on `supports_profraw_pool` and
+/// fall back to the plain `%p` form otherwise.
+fn profraw_file_pattern(file_stem: &str, config: &Config) -> String {
+    match active_profraw_pool_size(config) {
+        Some(pool_size) => format!("{}_%{}m.profraw", file_stem, pool_size),
+        None => format!("{}_%p.profraw", file_stem),
     }
+}
+
+/// Expected on-disk paths of a `%Nm` pool's member files for `file_stem`, so they can be reset
+/// before a run (see `RunningProcessHandle::new_with_pool_reset`). LLVM substitutes `%Nm` with
+/// an integer in `1..=N` chosen per process group, giving filenames `{file_stem}_{1..=N}.profraw`.
+fn pool_profraw_paths(root: &Path, file_stem: &str, pool_size: u32) -> Vec<PathBuf> {
+    (1..=pool_size)
+        .map(|i| root.join(format!("{}_{}.profraw", file_stem, i)))
+        .collect()
+}
+
+/// Builds just the instrumentation-specific variables tarpaulin injects for `test`: `RUST_BACKTRACE`
+/// (if verbose), `CARGO_PKG_*`, `CARGO_MANIFEST_DIR`, and, under the Llvm engine only, the
+/// `LLVM_PROFILE_FILE` pattern; other engines get no `LLVM_PROFILE_FILE` here. Does *not* include the ambient environment of the tarpaulin process
+/// itself. Shared by `coverage_envars`, which layers these over the full ambient environment
+/// for spawning a child, and `export_env`, which prints only these so it doesn't leak whatever
+/// happens to be set in the invoking shell.
+fn instrumentation_envars(test: &TestBinary, config: &Config) -> Vec<(String, String)> {
+    let mut envars: Vec<(String, String)> = Vec::new();
+
     if config.verbose {
         envars.push(("RUST_BACKTRACE".to_string(), "1".to_string()));
     }
-    argv.extend_from_slice(&config.varargs);
-    if config.color != Color::Auto {
-        argv.push("--color".to_string());
-        argv.push(config.color.to_string().to_ascii_lowercase());
-    }
-
     if let Some(s) = test.pkg_name() {
         envars.push(("CARGO_PKG_NAME".to_string(), s.to_string()));
     }
@@ -198,18 +388,78 @@ fn execute_test(test: &TestBinary, ignored: bool, config: &Config) -> Result<Tes
     if let Some(s) = test.manifest_dir() {
         envars.push(("CARGO_MANIFEST_DIR".to_string(), s.display().to_string()));
     }
+    if let TraceEngine::Llvm = config.engine() {
+        // Used for llvm coverage to avoid report naming clashes TODO could have clashes
+        // between runs
+        let stem = test.file_name();
+        envars.push((
+            "LLVM_PROFILE_FILE".to_string(),
+            profraw_file_pattern(&stem, config),
+        ));
+    }
+    envars
+}
+
+/// Builds the environment variables tarpaulin injects into a test process for the given
+/// engine, without actually spawning anything. Used by `execute_test` and `execute_test_nextest`
+/// (which adds its own `%p` `LLVM_PROFILE_FILE`); `get_doctest_coverage` builds its own
+/// environment and does not call this.
+pub fn coverage_envars(test: &TestBinary, config: &Config) -> Vec<(String, String)> {
+    let mut envars: Vec<(String, String)> = env::vars().collect();
+    envars.extend(instrumentation_envars(test, config));
+    envars
+}
+
+/// Implements `cargo tarpaulin export-env`: prints only the instrumentation variables
+/// `execute_test` would add on top of the ambient environment for this test binary, in a form
+/// a shell can `eval`, without running anything and without leaking the rest of the invoking
+/// shell's environment. This lets users reproduce tarpaulin's instrumentation under their own
+/// test harness or a debugger and feed the resulting profraws back into tarpaulin for
+/// reporting.
+pub fn export_env(test: &TestBinary, config: &Config) {
+    for (key, value) in instrumentation_envars(test, config) {
+        println!("export {}={}", key, shell_escape(&value));
+    }
+}
+
+fn shell_escape(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Launches the test executable
+fn execute_test(test: &TestBinary, ignored: bool, config: &Config) -> Result<TestHandle, RunError> {
+    info!("running {}", test.path().display());
+    let _ = match test.manifest_dir() {
+        Some(md) => env::set_current_dir(&md),
+        None => env::set_current_dir(&config.root()),
+    };
+
+    let envars = coverage_envars(test, config);
+    let mut argv = vec![];
+    if ignored {
+        argv.push("--ignored".to_string());
+    }
+    argv.extend_from_slice(&config.varargs);
+    if config.color != Color::Auto {
+        argv.push("--color".to_string());
+        argv.push(config.color.to_string().to_ascii_lowercase());
+    }
+
     match config.engine() {
         TraceEngine::Llvm => {
-            // Used for llvm coverage to avoid report naming clashes TODO could have clashes
-            // between runs
-            envars.push((
-                "LLVM_PROFILE_FILE".to_string(),
-                format!("{}_%p.profraw", test.file_name()),
-            ));
             let mut child = Command::new(test.path());
             child.envs(envars).args(&argv);
 
-            let hnd = RunningProcessHandle::new(test.path().to_path_buf(), &mut child, config)?;
+            let pool_profraws = match active_profraw_pool_size(config) {
+                Some(pool_size) => pool_profraw_paths(config.root(), &test.file_name(), pool_size),
+                None => Vec::new(),
+            };
+            let hnd = RunningProcessHandle::new_with_pool_reset(
+                test.path().to_path_buf(),
+                &mut child,
+                config,
+                &pool_profraws,
+            )?;
             Ok(hnd.into())
         }
         #[cfg(target_os = "linux")]
@@ -220,3 +470,75 @@ fn execute_test(test: &TestBinary, ignored: bool, config: &Config) -> Result<Tes
         ))),
     }
 }
+
+/// Strips cargo's disambiguating hash suffix (e.g. `mycrate-9f8d7e6c1a2b3c4d` -> `mycrate`) from
+/// a compiled test artifact's file stem. `TestBinary::file_name` is the on-disk filename, hash
+/// and all; nextest's `binary_id` filter predicate matches its own catalog id, which is derived
+/// from cargo metadata and never includes that hash.
+fn strip_cargo_hash_suffix(file_stem: &str) -> &str {
+    match file_stem.rsplit_once('-') {
+        Some((name, suffix)) if suffix.len() >= 8 && suffix.chars().all(|c| c.is_ascii_hexdigit()) => {
+            name
+        }
+        _ => file_stem,
+    }
+}
+
+/// Best-effort reconstruction of the nextest `binary_id` for `test`, so a single test binary
+/// can be filtered out of a `cargo nextest run` invocation that would otherwise cover the whole
+/// package. Nextest's real id comes from its own cargo-metadata-derived build plan; lacking
+/// access to that here, we approximate it as `<package>::<target>` (or bare `<package>` for the
+/// package's unit-test target, which nextest identifies without a `::` suffix) by recovering
+/// the target name from the hashed file stem.
+fn nextest_binary_id(test: &TestBinary) -> String {
+    let file_stem = test.file_name();
+    let target = strip_cargo_hash_suffix(&file_stem);
+    match test.pkg_name() {
+        Some(pkg) if pkg != target => format!("{}::{}", pkg, target),
+        Some(pkg) => pkg.to_string(),
+        None => target.to_string(),
+    }
+}
+
+/// Launches the test binary under `cargo nextest run` instead of invoking it directly.
+///
+/// Nextest runs each test case in its own process, so unlike the plain LLVM path there's no
+/// single child to attribute a `.profraw` to. Instead we let every spawned process write its
+/// own file via the `%p` pid specifier in `LLVM_PROFILE_FILE` and merge the whole set once
+/// the nextest runner exits.
+fn execute_test_nextest(
+    test: &TestBinary,
+    ignored: bool,
+    config: &Config,
+) -> Result<TestHandle, RunError> {
+    info!("running {} via cargo nextest", test.path().display());
+    let _ = match test.manifest_dir() {
+        Some(md) => env::set_current_dir(&md),
+        None => env::set_current_dir(&config.root()),
+    };
+
+    let mut envars = coverage_envars(test, config);
+    // Each test process gets its own profraw, keyed on pid.
+    envars.push((
+        "LLVM_PROFILE_FILE".to_string(),
+        format!("{}_%p.profraw", test.file_name()),
+    ));
+
+    let mut cmd = Command::new("cargo");
+    cmd.arg("nextest").arg("run").arg("--no-fail-fast");
+    if ignored {
+        cmd.arg("--run-ignored").arg("ignored-only");
+    }
+    if let Some(s) = test.pkg_name() {
+        cmd.arg("--package").arg(s);
+    }
+    // launch_test calls us once per TestBinary, so without this filter a package with
+    // multiple test targets would have its whole suite rerun once per target. Restrict each
+    // invocation to just the binary being iterated.
+    cmd.arg("-E").arg(format!("binary_id({})", nextest_binary_id(test)));
+    cmd.args(&config.varargs);
+    cmd.envs(envars);
+
+    let hnd = RunningProcessHandle::new(test.path().to_path_buf(), &mut cmd, config)?;
+    Ok(hnd.into())
+}